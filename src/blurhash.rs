@@ -0,0 +1,163 @@
+/**
+    This file is part of Thumbnailer.
+
+    Thumbnailer is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License.
+
+    Thumbnailer is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Thumbnailer.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use std::f64::consts::PI;
+
+const BASE83: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Compute a BlurHash placeholder string for a decoded image.
+///
+/// `pixels` is an interleaved 8-bit buffer (channels are inferred from its
+/// length); `cx` and `cy` are the number of horizontal and vertical components
+/// (each clamped to 1..=9).
+pub fn blurhash(pixels: &[u8], width: u32, height: u32, cx: u32, cy: u32) -> String {
+    let cx = cx.clamp(1, 9);
+    let cy = cy.clamp(1, 9);
+    let channels = (pixels.len() / (width as usize * height as usize)).max(3);
+
+    let mut factors = Vec::with_capacity((cx * cy) as usize);
+    for j in 0..cy {
+        for i in 0..cx {
+            factors.push(multiply_basis(i, j, pixels, width, height, channels));
+        }
+    }
+
+    let dc = factors[0];
+    let ac = &factors[1..];
+
+    let mut result = String::new();
+
+    let size_flag = (cx - 1) + (cy - 1) * 9;
+    result.push_str(&encode_base83(size_flag, 1));
+
+    let maximum_value;
+    if ac.is_empty() {
+        maximum_value = 1.0;
+        result.push_str(&encode_base83(0, 1));
+    } else {
+        let actual_max = ac
+            .iter()
+            .map(|f| f[0].abs().max(f[1].abs()).max(f[2].abs()))
+            .fold(0.0_f64, f64::max);
+        let quantised = ((actual_max * 166.0 - 0.5).floor() as i64).clamp(0, 82);
+        maximum_value = (quantised + 1) as f64 / 166.0;
+        result.push_str(&encode_base83(quantised as u32, 1));
+    }
+
+    result.push_str(&encode_base83(encode_dc(dc), 4));
+    for factor in ac {
+        result.push_str(&encode_base83(encode_ac(*factor, maximum_value), 2));
+    }
+
+    result
+}
+
+/// Accumulate a single basis factor over every pixel of the image.
+fn multiply_basis(
+    i: u32,
+    j: u32,
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    channels: usize,
+) -> [f64; 3] {
+    let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+    let scale = normalisation / (width as f64 * height as f64);
+
+    let mut r = 0.0;
+    let mut g = 0.0;
+    let mut b = 0.0;
+    for y in 0..height {
+        for x in 0..width {
+            let basis = (PI * i as f64 * x as f64 / width as f64).cos()
+                * (PI * j as f64 * y as f64 / height as f64).cos();
+            let offset = (y as usize * width as usize + x as usize) * channels;
+            r += basis * srgb_to_linear(pixels[offset]);
+            g += basis * srgb_to_linear(pixels[offset + 1]);
+            b += basis * srgb_to_linear(pixels[offset + 2]);
+        }
+    }
+
+    [r * scale, g * scale, b * scale]
+}
+
+fn encode_dc(factor: [f64; 3]) -> u32 {
+    let r = linear_to_srgb(factor[0]) as u32;
+    let g = linear_to_srgb(factor[1]) as u32;
+    let b = linear_to_srgb(factor[2]) as u32;
+    (r << 16) + (g << 8) + b
+}
+
+fn encode_ac(factor: [f64; 3], maximum_value: f64) -> u32 {
+    let quant = |value: f64| -> u32 {
+        ((sign_pow(value / maximum_value, 0.5) * 9.0 + 9.5).floor() as i64).clamp(0, 18) as u32
+    };
+    quant(factor[0]) * 19 * 19 + quant(factor[1]) * 19 + quant(factor[2])
+}
+
+fn sign_pow(value: f64, exp: f64) -> f64 {
+    value.abs().powf(exp).copysign(value)
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let c = value as f64 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encode a linear-light sample (0..=1) onto the sRGB transfer curve.
+pub(crate) fn linear_to_srgb(value: f64) -> u8 {
+    let c = value.clamp(0.0, 1.0);
+    let v = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (v * 255.0 + 0.5) as u8
+}
+
+fn encode_base83(value: u32, length: usize) -> String {
+    let mut result = String::with_capacity(length);
+    for i in 1..=length {
+        let digit = (value as usize / 83usize.pow((length - i) as u32)) % 83;
+        result.push(BASE83[digit] as char);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blurhash::blurhash;
+
+    #[test]
+    fn test_solid_colour() {
+        // A solid 2x2 RGB block encodes to a well-formed hash whose length
+        // matches the 1x1-component layout (1 + 1 + 4 + 2 * (cx*cy - 1)).
+        let pixels: [u8; 12] = [255, 0, 0, 255, 0, 0, 255, 0, 0, 255, 0, 0];
+        let hash = blurhash(&pixels, 2, 2, 1, 1);
+        assert_eq!(hash.len(), 6);
+    }
+
+    #[test]
+    fn test_component_count() {
+        let pixels = vec![128u8; 3 * 4 * 4];
+        let hash = blurhash(&pixels, 4, 4, 4, 3);
+        assert_eq!(hash.len(), 2 + 4 + 2 * (4 * 3 - 1));
+    }
+}
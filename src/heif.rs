@@ -0,0 +1,66 @@
+/**
+    This file is part of Thumbnailer.
+
+    Thumbnailer is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License.
+
+    Thumbnailer is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Thumbnailer.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+use std::path::Path;
+
+/// Decode a HEIF/HEIC/AVIF file into a `DynamicImage`.
+///
+/// HEIF rows are padded to the decoder's stride, so each row is copied without
+/// its trailing padding into a contiguous buffer before being handed to the
+/// `image` crate.
+pub fn decode(path: &Path) -> Result<image::DynamicImage, String> {
+    let lib = LibHeif::new();
+    let context = HeifContext::read_from_file(path.to_str().unwrap())
+        .map_err(|e| format!("Failed to read HEIF file: {}", e))?;
+    let handle = context
+        .primary_image_handle()
+        .map_err(|e| format!("Failed to obtain primary image handle: {}", e))?;
+
+    let has_alpha = handle.has_alpha_channel();
+    let chroma = if has_alpha {
+        RgbChroma::Rgba
+    } else {
+        RgbChroma::Rgb
+    };
+    let image = lib
+        .decode(&handle, ColorSpace::Rgb(chroma), None)
+        .map_err(|e| format!("Failed to decode HEIF image: {}", e))?;
+
+    let width = image.width();
+    let height = image.height();
+    let planes = image.planes();
+    let plane = planes
+        .interleaved
+        .ok_or_else(|| "HEIF image has no interleaved plane".to_owned())?;
+
+    let channels = if has_alpha { 4 } else { 3 };
+    let row_bytes = width as usize * channels;
+    let stride = plane.stride;
+    let mut buffer = Vec::with_capacity(row_bytes * height as usize);
+    for y in 0..height as usize {
+        let start = y * stride;
+        buffer.extend_from_slice(&plane.data[start..start + row_bytes]);
+    }
+
+    if has_alpha {
+        image::ImageBuffer::<image::Rgba<u8>, _>::from_raw(width, height, buffer)
+            .map(image::DynamicImage::ImageRgba8)
+    } else {
+        image::ImageBuffer::<image::Rgb<u8>, _>::from_raw(width, height, buffer)
+            .map(image::DynamicImage::ImageRgb8)
+    }
+    .ok_or_else(|| "Failed to build image from decoded HEIF buffer".to_owned())
+}
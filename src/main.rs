@@ -13,21 +13,32 @@
     You should have received a copy of the GNU General Public License
     along with Thumbnailer.  If not, see <http://www.gnu.org/licenses/>.
 */
+mod blurhash;
+mod png;
 mod thumbnailer;
-use crate::thumbnailer::{ThumbSize, Thumbnailer};
+#[cfg(feature = "heif")]
+mod heif;
+#[cfg(feature = "raw")]
+mod raw;
+#[cfg(feature = "video")]
+mod video;
+use crate::thumbnailer::{MediaKind, Outcome, ThumbSize, Thumbnailer};
 
 use docopt::Docopt;
 use env_logger::Env;
 use log::{debug, error, info, warn};
 use serde::Deserialize;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const USAGE: &'static str = "
 Thumbnailer.
 
 Usage:
-  thumbnailer [-v] [-r] [--jobs=<num>] (-n|-l|-n -l) (--output=<dir>|-x) <directory>
-  thumbnailer [-v] [--jobs=<num>] (-n|-l|-n -l) -s <directory>
+  thumbnailer [-v] [-r] [-f] [-q] [--progress] [--jobs=<num>] (-n|-l|-n -l) (--output=<dir>|-x) <directory>
+  thumbnailer [-v] [-f] [-q] [--progress] [--jobs=<num>] (-n|-l|-n -l) -s <directory>
   thumbnailer (-h | --help)
   thumbnailer (-v | --verbose)
 
@@ -39,6 +50,9 @@ Options:
   -r --recursive      Recursive scan.
   -n --normal         Generate normal thumbs.
   -l --large          Generate large thumbs.
+  -f --force          Regenerate thumbnails even when up to date.
+  -q --quiet          Suppress the progress reporter.
+  --progress          Report throughput and ETA to stderr.
   -j --jobs=<num>     Number of parallel jobs [default: 1]
   -o --output=<dir>   Output to custom directory
   -x --xdg            Output to XDG directory
@@ -51,6 +65,9 @@ struct Args {
     flag_verbose: bool,
     flag_debug: bool,
     flag_recursive: bool,
+    flag_force: bool,
+    flag_quiet: bool,
+    flag_progress: bool,
     flag_normal: bool,
     flag_large: bool,
     flag_workers: Option<u32>,
@@ -89,20 +106,126 @@ fn get_cache_destination(args: &Args) -> Result<PathBuf, String> {
     }
 }
 
+fn extension_of(entry: &walkdir::DirEntry) -> Option<String> {
+    entry
+        .path()
+        .extension()
+        .map(|e| e.to_str().unwrap().to_lowercase())
+}
+
 fn is_image(entry: &walkdir::DirEntry) -> bool {
-    let extension = match entry.path().extension() {
+    let extension = match extension_of(entry) {
+        Some(e) => e,
+        _ => return false,
+    };
+    if extension == "jpg" || extension == "jpeg" || extension == "png" {
+        return true;
+    }
+    #[cfg(feature = "heif")]
+    if extension == "heic" || extension == "heif" || extension == "avif" {
+        return true;
+    }
+    #[cfg(feature = "raw")]
+    if is_raw_extension(&extension) {
+        return true;
+    }
+    false
+}
+
+#[cfg(feature = "raw")]
+fn is_raw_extension(extension: &str) -> bool {
+    matches!(
+        extension,
+        "cr2" | "nef" | "arw" | "dng" | "rw2" | "orf" | "raf"
+    )
+}
+
+#[cfg(feature = "video")]
+fn is_video(entry: &walkdir::DirEntry) -> bool {
+    let extension = match extension_of(entry) {
         Some(e) => e,
         _ => return false,
     };
-    let extensions = extension.to_str().unwrap().to_lowercase();
-    extensions == "jpg" || extensions == "jpeg" || extensions == "png"
+    extension == "mp4" || extension == "mkv" || extension == "webm" || extension == "mov"
+}
+
+/// Classify a directory entry into the decoder path it needs, or `None` when
+/// it is not a media file we know how to thumbnail.
+fn media_kind(entry: &walkdir::DirEntry) -> Option<MediaKind> {
+    if is_image(entry) {
+        return Some(MediaKind::Image);
+    }
+    #[cfg(feature = "video")]
+    if is_video(entry) {
+        return Some(MediaKind::Video);
+    }
+    None
 }
 
 struct Work {
     path: PathBuf,
     size: ThumbSize,
+    kind: MediaKind,
     destination: PathBuf,
     use_full_path_for_md5: bool,
+    force: bool,
+}
+
+/// Shared tallies read by the progress reporter: `queued` is updated while
+/// walking the directory, the other counters by the worker threads.
+#[derive(Clone, Default)]
+struct Counters {
+    queued: Arc<AtomicUsize>,
+    succeeded: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+    skipped: Arc<AtomicUsize>,
+}
+
+impl Counters {
+    fn processed(&self) -> usize {
+        self.succeeded.load(Ordering::Relaxed)
+            + self.failed.load(Ordering::Relaxed)
+            + self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+/// Spawn a thread that periodically reports throughput and an ETA to stderr
+/// until `running` is cleared, printing a final summary when it stops.
+fn spawn_reporter(
+    counters: Counters,
+    running: Arc<AtomicBool>,
+    start: Instant,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        while running.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_secs(1));
+            let processed = counters.processed();
+            let queued = counters.queued.load(Ordering::Relaxed);
+            let elapsed = start.elapsed().as_secs_f64();
+            let rate = if elapsed > 0.0 {
+                processed as f64 / elapsed
+            } else {
+                0.0
+            };
+            let eta = if rate > 0.0 {
+                (queued.saturating_sub(processed)) as f64 / rate
+            } else {
+                0.0
+            };
+            eprintln!(
+                "Progress: {}/{} ({:.1} img/s, ETA {:.0}s)",
+                processed, queued, rate, eta
+            );
+        }
+        eprintln!(
+            "Done: {} succeeded, {} failed, {} skipped ({} total, {:.1}s)",
+            counters.succeeded.load(Ordering::Relaxed),
+            counters.failed.load(Ordering::Relaxed),
+            counters.skipped.load(Ordering::Relaxed),
+            counters.queued.load(Ordering::Relaxed),
+            start.elapsed().as_secs_f64(),
+        );
+    })
 }
 
 fn main() {
@@ -157,9 +280,11 @@ fn main() {
 
     debug!("Output directory is {}", destination.to_str().unwrap());
 
-    // Create directories
-    for size in args.sizes() {
-        let size_directory = destination.join(size.name());
+    // Create directories, including the freedesktop "fail" directory where
+    // markers for un-thumbnailable files are recorded.
+    let mut directories: Vec<PathBuf> = args.sizes().iter().map(|s| destination.join(s.name())).collect();
+    directories.push(destination.join("fail").join("thumbnailer"));
+    for size_directory in directories {
         if !size_directory.exists() {
             debug!(
                 "Cache directory {} does not exists",
@@ -192,10 +317,21 @@ fn main() {
         walk = walk.max_depth(1);
     }
 
+    // Shared counters and the optional progress reporter.
+    let counters = Counters::default();
+    let reporter = if args.flag_progress && !args.flag_quiet {
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = spawn_reporter(counters.clone(), running.clone(), Instant::now());
+        Some((running, handle))
+    } else {
+        None
+    };
+
     let (sender, receiver) = crossbeam::channel::bounded(jobs * 2);
     let mut workers = Vec::new();
     for _ in 0..jobs {
         let r = receiver.clone();
+        let counters = counters.clone();
         workers.push(std::thread::spawn(move || loop {
             let work: Work = match r.recv() {
                 Ok(v) => v,
@@ -205,19 +341,35 @@ fn main() {
                 work.path.clone(),
                 work.destination.clone(),
                 work.size,
+                work.kind,
                 work.use_full_path_for_md5,
+                work.force,
             ) {
-                Ok(_) => info!(
-                    "Created {} thumbnail for {}",
-                    work.size.name(),
-                    work.path.canonicalize().unwrap().to_str().unwrap()
-                ),
-                Err(e) => error!(
-                    "Failed to create {} thumbnail for {}. Error {}",
-                    work.size.name(),
-                    work.path.to_str().unwrap(),
-                    e
-                ),
+                Ok(Outcome::Generated) => {
+                    counters.succeeded.fetch_add(1, Ordering::Relaxed);
+                    info!(
+                        "Created {} thumbnail for {}",
+                        work.size.name(),
+                        work.path.canonicalize().unwrap().to_str().unwrap()
+                    )
+                }
+                Ok(Outcome::Skipped) => {
+                    counters.skipped.fetch_add(1, Ordering::Relaxed);
+                    debug!(
+                        "Skipped {} thumbnail for {}",
+                        work.size.name(),
+                        work.path.to_str().unwrap()
+                    )
+                }
+                Err(e) => {
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                    error!(
+                        "Failed to create {} thumbnail for {}. Error {}",
+                        work.size.name(),
+                        work.path.to_str().unwrap(),
+                        e
+                    )
+                }
             }
         }))
     }
@@ -225,16 +377,18 @@ fn main() {
     // Walk filesystem
     walk.into_iter()
         .filter_map(|e| e.ok())
-        .filter(|e| is_image(e))
-        .map(|e| e.path().to_path_buf())
-        .for_each(|p| {
+        .filter_map(|e| media_kind(&e).map(|k| (e.path().to_path_buf(), k)))
+        .for_each(|(p, kind)| {
             for size in args.sizes() {
+                counters.queued.fetch_add(1, Ordering::Relaxed);
                 sender
                     .send(Work {
                         path: p.clone(),
                         destination: destination.clone(),
                         use_full_path_for_md5: !args.flag_shared,
                         size,
+                        kind,
+                        force: args.flag_force,
                     })
                     .unwrap()
             }
@@ -243,4 +397,10 @@ fn main() {
     for w in workers {
         w.join().unwrap();
     }
+
+    // Stop the reporter and let it print the final summary line.
+    if let Some((running, handle)) = reporter {
+        running.store(false, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
 }
@@ -14,10 +14,21 @@
     along with Thumbnailer.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use std::io::Read;
+use log::debug;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
 
 const PNG_SIGNATURE: [u8; 8] =  [137, 80, 78, 71, 13, 10, 26, 10];
 
+/// Errors that can arise while decoding a PNG stream.
+#[derive(Debug)]
+enum PngError {
+    BadSignature,
+    Truncated,
+    InvalidChunkType,
+    CrcMismatch { kind: String, expected: u32, found: u32 },
+}
+
 struct Chunk {
     kind: String,
     length: u32,
@@ -25,31 +36,38 @@ struct Chunk {
     crc: u32
 }
 
-fn decode_chunk(file: &mut std::fs::File) -> std::result::Result<Chunk, ()> {
-    let mut temp = vec![];
+fn decode_chunk(file: &mut std::fs::File) -> std::result::Result<Chunk, PngError> {
     let mut chunk_length: [u8; 4] = Default::default();
     let mut chunk_kind: [u8; 4] = Default::default();
     let mut chunk_crc: [u8; 4] = Default::default();
     let mut chunk_data: Vec<u8> = vec![];
 
-    file.read_exact(&mut chunk_length);
-    let chunk_length = unsafe { std::mem::transmute::<[u8; 4], u32>(chunk_length).to_be()};
+    file.read_exact(&mut chunk_length).map_err(|_| PngError::Truncated)?;
+    let chunk_length = u32::from_be_bytes(chunk_length);
 
-    file.read_exact(&mut chunk_kind).map_err(|_| ())?;
-    temp = chunk_kind.to_vec();
-    let chunk_type = String::from_utf8(chunk_kind.to_vec()).map_err(|_|())?;
+    file.read_exact(&mut chunk_kind).map_err(|_| PngError::Truncated)?;
+    let chunk_type = String::from_utf8(chunk_kind.to_vec()).map_err(|_| PngError::InvalidChunkType)?;
 
     if chunk_length > 0 {
         chunk_data.resize(chunk_length as usize, 0);
-        file.read_exact(&mut chunk_data);
-        temp.append(&mut chunk_data.clone());
+        file.read_exact(&mut chunk_data).map_err(|_| PngError::Truncated)?;
     }
 
-    file.read_exact(&mut chunk_crc).map_err(|_|())?;
-    let chunk_crc = unsafe { std::mem::transmute::<[u8; 4], u32>(chunk_crc).to_be()};
-
+    file.read_exact(&mut chunk_crc).map_err(|_| PngError::Truncated)?;
+    let chunk_crc = u32::from_be_bytes(chunk_crc);
 
+    // The CRC covers the chunk type followed by the chunk data.
     let crc = CRC::new();
+    let mut crc_input = chunk_kind.to_vec();
+    crc_input.extend_from_slice(&chunk_data);
+    let computed = crc.crc(&crc_input);
+    if computed != chunk_crc {
+        return Err(PngError::CrcMismatch {
+            kind: chunk_type,
+            expected: chunk_crc,
+            found: computed,
+        });
+    }
 
     Ok(Chunk {
         length: chunk_length,
@@ -59,6 +77,203 @@ fn decode_chunk(file: &mut std::fs::File) -> std::result::Result<Chunk, ()> {
     })
 }
 
+/// Read and validate a PNG signature from the head of the stream.
+fn decode_signature(file: &mut std::fs::File) -> std::result::Result<(), PngError> {
+    let mut signature: [u8; 8] = Default::default();
+    file.read_exact(&mut signature).map_err(|_| PngError::Truncated)?;
+    if signature != PNG_SIGNATURE {
+        return Err(PngError::BadSignature);
+    }
+    Ok(())
+}
+
+/// A decoded PNG, holding its ancillary chunks keyed by kind so callers can
+/// query metadata such as the embedded Exif block or ICC colour profile.
+pub(crate) struct Png {
+    chunks: HashMap<String, Vec<u8>>,
+}
+
+impl Png {
+    /// Decode a PNG, buffering chunks by kind while scanning up to `IEND`.
+    /// Only the first chunk of each kind is kept; the bulky `IDAT` payloads
+    /// are not retained.
+    pub(crate) fn decode(file: &mut std::fs::File) -> std::result::Result<Png, PngError> {
+        decode_signature(file)?;
+        let mut chunks = HashMap::new();
+        loop {
+            let chunk = decode_chunk(file)?;
+            if chunk.kind == "IEND" {
+                break;
+            }
+            if chunk.kind != "IDAT" {
+                chunks.entry(chunk.kind).or_insert(chunk.data);
+            }
+        }
+        Ok(Png { chunks })
+    }
+
+    /// The parsed Exif metadata carried by the `eXIf` chunk, if present.
+    pub(crate) fn exif(&self) -> Option<exif::ExifData> {
+        self.chunks.get("eXIf").and_then(|d| exif::parse(d))
+    }
+
+    /// The embedded ICC profile carried by the `iCCP` chunk, inflated from its
+    /// zlib-compressed form. The payload is `profile-name\0 method stream`.
+    fn icc_profile(&self) -> Option<Vec<u8>> {
+        let data = self.chunks.get("iCCP")?;
+        let nul = data.iter().position(|b| *b == 0)?;
+        // Skip the NUL terminator and the single compression-method byte.
+        let stream = data.get(nul + 2..)?;
+        miniz_oxide::inflate::decompress_to_vec_zlib(stream).ok()
+    }
+
+    /// Whether the image declares the standard sRGB colour space via an `sRGB`
+    /// chunk.
+    fn is_srgb(&self) -> bool {
+        self.chunks.contains_key("sRGB")
+    }
+
+    /// The gamma value from the `gAMA` chunk, expressed in the chunk's fixed
+    /// point (gamma times 100000).
+    fn gamma(&self) -> Option<u32> {
+        self.chunks
+            .get("gAMA")
+            .filter(|d| d.len() >= 4)
+            .map(|d| u32::from_be_bytes([d[0], d[1], d[2], d[3]]))
+    }
+
+    /// Whether the image carries a colour profile that is not plain sRGB, so a
+    /// caller may decide to colour-manage it before encoding the thumbnail.
+    fn is_non_srgb(&self) -> bool {
+        self.chunks.contains_key("iCCP") && !self.is_srgb()
+    }
+}
+
+/// Colour-manage a decoded image into sRGB using the metadata `chunks` scanned
+/// alongside it. An explicit `sRGB` chunk needs no work; a `gAMA` chunk is
+/// used to rescale samples onto the sRGB transfer curve. A wide-gamut `iCCP`
+/// profile with no `gAMA` fallback would need full ICC matrix parsing to
+/// colour-manage correctly, which is out of scope for this self-contained
+/// decoder, so such images are returned unmanaged.
+pub(crate) fn color_manage_to_srgb(image: image::DynamicImage, chunks: &Png) -> image::DynamicImage {
+    if chunks.is_srgb() {
+        return image;
+    }
+
+    let source_gamma = match chunks.gamma() {
+        Some(g) => g as f64 / 100_000.0,
+        None => {
+            if chunks.is_non_srgb() {
+                debug!(
+                    "PNG carries a {}-byte ICC profile with no gAMA fallback; decoding without colour management",
+                    chunks.icc_profile().map(|p| p.len()).unwrap_or(0)
+                );
+            }
+            return image;
+        }
+    };
+
+    let mut lut = [0u8; 256];
+    for (v, slot) in lut.iter_mut().enumerate() {
+        let linear = (v as f64 / 255.0).powf(1.0 / source_gamma);
+        *slot = crate::blurhash::linear_to_srgb(linear);
+    }
+
+    let mut rgba = image.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        pixel[0] = lut[pixel[0] as usize];
+        pixel[1] = lut[pixel[1] as usize];
+        pixel[2] = lut[pixel[2] as usize];
+    }
+    image::DynamicImage::ImageRgba8(rgba)
+}
+
+/// Minimal TIFF/Exif parser, sufficient to recover the display orientation
+/// from the raw block carried by a PNG `eXIf` chunk.
+mod exif {
+    /// The subset of Exif metadata the thumbnail pipeline needs.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct ExifData {
+        /// The Exif orientation tag (1 = top-left / no transform).
+        pub orientation: u16,
+    }
+
+    fn read_u16(data: &[u8], offset: usize, little_endian: bool) -> u16 {
+        let bytes = [data[offset], data[offset + 1]];
+        if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    }
+
+    fn read_u32(data: &[u8], offset: usize, little_endian: bool) -> u32 {
+        let bytes = [
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ];
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }
+
+    /// Parse a raw TIFF/Exif block and extract the orientation tag, defaulting
+    /// to 1 (top-left) when the tag is absent.
+    pub fn parse(data: &[u8]) -> Option<ExifData> {
+        if data.len() < 8 {
+            return None;
+        }
+        let little_endian = match &data[0..2] {
+            b"II" => true,
+            b"MM" => false,
+            _ => return None,
+        };
+        if read_u16(data, 2, little_endian) != 42 {
+            return None;
+        }
+
+        let ifd_offset = read_u32(data, 4, little_endian) as usize;
+        if ifd_offset + 2 > data.len() {
+            return None;
+        }
+        let entry_count = read_u16(data, ifd_offset, little_endian) as usize;
+
+        let mut orientation = 1u16;
+        for i in 0..entry_count {
+            let entry = ifd_offset + 2 + i * 12;
+            if entry + 12 > data.len() {
+                break;
+            }
+            // Tag 0x0112 is Orientation; its value sits in the first two bytes
+            // of the 4-byte value field.
+            if read_u16(data, entry, little_endian) == 0x0112 {
+                orientation = read_u16(data, entry + 8, little_endian);
+            }
+        }
+
+        Some(ExifData { orientation })
+    }
+}
+
+/// Rotate/flip a decoded image to its upright display orientation per the
+/// Exif orientation tag (values 1-8; 1 is already upright and left as-is).
+pub(crate) fn apply_orientation(image: image::DynamicImage, orientation: u16) -> image::DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
 struct CRC {
     crc_table: [u32;256]
 }
@@ -102,10 +317,197 @@ impl CRC {
     }
 }
 
+/// The pixel layouts the encoder understands.
+#[derive(Copy, Clone)]
+pub(crate) enum ColorType {
+    Gray,
+    Rgb,
+    Rgba,
+}
+
+impl ColorType {
+    fn channels(&self) -> usize {
+        match self {
+            ColorType::Gray => 1,
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+        }
+    }
+
+    /// The PNG `IHDR` colour-type code.
+    fn code(&self) -> u8 {
+        match self {
+            ColorType::Gray => 0,
+            ColorType::Rgb => 2,
+            ColorType::Rgba => 6,
+        }
+    }
+}
+
+/// Append a PNG chunk (`length`, `type`, `data`, trailing CRC) to `out`.
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = CRC::new();
+    let mut crc_input = kind.to_vec();
+    crc_input.extend_from_slice(data);
+    out.extend_from_slice(&crc.crc(&crc_input).to_be_bytes());
+}
+
+/// Adler-32 checksum of `data`, as required by the zlib trailer.
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// Wrap `data` in a zlib stream built entirely from "stored" (uncompressed)
+/// deflate blocks, avoiding any external compression dependency.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    if data.is_empty() {
+        out.push(0x01);
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&(!0u16).to_le_bytes());
+    } else {
+        let mut blocks = data.chunks(65535).peekable();
+        while let Some(block) = blocks.next() {
+            let final_flag = if blocks.peek().is_none() { 1u8 } else { 0u8 };
+            let len = block.len() as u16;
+            out.push(final_flag);
+            out.extend_from_slice(&len.to_le_bytes());
+            out.extend_from_slice(&(!len).to_le_bytes());
+            out.extend_from_slice(block);
+        }
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Build a `tEXt` chunk payload, `keyword\0text`.
+fn text_chunk(keyword: &str, text: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(keyword.len() + 1 + text.len());
+    data.extend_from_slice(keyword.as_bytes());
+    data.push(0);
+    data.extend_from_slice(text.as_bytes());
+    data
+}
+
+/// Encode an interleaved 8-bit `pixels` buffer into a complete PNG byte stream.
+pub(crate) fn encode(pixels: &[u8], width: u32, height: u32, color: ColorType) -> Vec<u8> {
+    encode_with_text(pixels, width, height, color, &[])
+}
+
+/// Encode a thumbnail, inserting the freedesktop provenance `tEXt` chunks
+/// (`Thumb::URI`, `Thumb::MTime` and `Thumb::Size`) ahead of the image data.
+pub(crate) fn encode_thumbnail(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    source_uri: &str,
+    mtime: u64,
+    size: u64,
+) -> Vec<u8> {
+    let texts = [
+        ("Thumb::URI", source_uri.to_owned()),
+        ("Thumb::MTime", mtime.to_string()),
+        ("Thumb::Size", size.to_string()),
+    ];
+    encode_with_text(pixels, width, height, color, &texts)
+}
+
+/// Encode an image, writing the given `tEXt` chunks between `IHDR` and `IDAT`.
+pub(crate) fn encode_with_text(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    texts: &[(&str, String)],
+) -> Vec<u8> {
+    let row_bytes = width as usize * color.channels();
+
+    // Build the raw scanlines, each prefixed with a filter-type byte (0 = none).
+    let mut raw = Vec::with_capacity((row_bytes + 1) * height as usize);
+    for y in 0..height as usize {
+        raw.push(0);
+        let start = y * row_bytes;
+        raw.extend_from_slice(&pixels[start..start + row_bytes]);
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&PNG_SIGNATURE);
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.push(8); // bit depth
+    ihdr.push(color.code());
+    ihdr.push(0); // compression method
+    ihdr.push(0); // filter method
+    ihdr.push(0); // interlace method
+    write_chunk(&mut out, b"IHDR", &ihdr);
+
+    for (keyword, text) in texts {
+        write_chunk(&mut out, b"tEXt", &text_chunk(keyword, text));
+    }
+
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// Scan only the leading `tEXt` chunks of a thumbnail PNG into a keyword -> text
+/// map, stopping at the first `IDAT` (ancillary chunks precede the image data),
+/// seeking past the data of other chunks rather than reading them.
+pub(crate) fn read_text_chunks(
+    file: &mut std::fs::File,
+) -> std::result::Result<HashMap<String, String>, PngError> {
+    decode_signature(file)?;
+
+    let mut result = HashMap::new();
+    loop {
+        let mut length: [u8; 4] = Default::default();
+        let mut kind: [u8; 4] = Default::default();
+        file.read_exact(&mut length).map_err(|_| PngError::Truncated)?;
+        file.read_exact(&mut kind).map_err(|_| PngError::Truncated)?;
+        let length = u32::from_be_bytes(length) as usize;
+        let kind = String::from_utf8(kind.to_vec()).map_err(|_| PngError::InvalidChunkType)?;
+
+        if kind == "IDAT" || kind == "IEND" {
+            break;
+        }
+
+        if kind == "tEXt" {
+            let mut data = vec![0u8; length];
+            file.read_exact(&mut data).map_err(|_| PngError::Truncated)?;
+            file.seek(SeekFrom::Current(4)).map_err(|_| PngError::Truncated)?;
+            if let Some(nul) = data.iter().position(|b| *b == 0) {
+                if let (Ok(keyword), Ok(text)) = (
+                    String::from_utf8(data[..nul].to_vec()),
+                    String::from_utf8(data[nul + 1..].to_vec()),
+                ) {
+                    result.insert(keyword, text);
+                }
+            }
+        } else {
+            file.seek(SeekFrom::Current(length as i64 + 4))
+                .map_err(|_| PngError::Truncated)?;
+        }
+    }
+
+    Ok(result)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::png::{PNG_SIGNATURE, decode_chunk, CRC};
-    use std::io::{Read, Seek};
+    use crate::png::{encode, decode_chunk, ColorType, PNG_SIGNATURE};
+    use std::io::{Read, Seek, Write};
     use std::path::PathBuf;
 
     #[test]
@@ -130,4 +532,96 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        // A 2x2 RGB image.
+        let pixels: [u8; 12] = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+        let bytes = encode(&pixels, 2, 2, ColorType::Rgb);
+        assert_eq!(&bytes[0..8], &PNG_SIGNATURE);
+
+        let path = std::env::temp_dir().join("thumbnailer_encode_roundtrip.png");
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(&bytes)
+            .unwrap();
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        let mut signature: [u8; 8] = Default::default();
+        file.read_exact(&mut signature).unwrap();
+        assert_eq!(signature, PNG_SIGNATURE);
+
+        // Every chunk must decode with a valid CRC and terminate at IEND.
+        let mut kinds = Vec::new();
+        loop {
+            let chunk = decode_chunk(&mut file).unwrap();
+            kinds.push(chunk.kind.clone());
+            if chunk.kind == "IEND" {
+                break;
+            }
+        }
+        assert_eq!(kinds, vec!["IHDR", "IDAT", "IEND"]);
+    }
+
+    #[test]
+    fn test_thumbnail_text_chunks() {
+        use crate::png::{encode_thumbnail, read_text_chunks};
+
+        let pixels: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let bytes = encode_thumbnail(
+            &pixels,
+            2,
+            2,
+            ColorType::Rgb,
+            "file:///home/jens/photo.png",
+            1_700_000_000,
+            42,
+        );
+
+        let path = std::env::temp_dir().join("thumbnailer_text_chunks.png");
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(&bytes)
+            .unwrap();
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        let chunks = read_text_chunks(&mut file).unwrap();
+        assert_eq!(
+            chunks.get("Thumb::URI").map(String::as_str),
+            Some("file:///home/jens/photo.png")
+        );
+        assert_eq!(
+            chunks.get("Thumb::MTime").map(String::as_str),
+            Some("1700000000")
+        );
+        assert_eq!(chunks.get("Thumb::Size").map(String::as_str), Some("42"));
+    }
+
+    #[test]
+    fn test_color_manage_applies_inverse_gamma() {
+        use crate::png::{color_manage_to_srgb, Png};
+        use image::GenericImageView;
+        use std::collections::HashMap;
+
+        // A `gAMA` chunk stores the encoding gamma (typically 45455 ->
+        // 0.45455); recovering linear light is sample^(1/file_gamma), not
+        // sample^file_gamma. Pin the direction: applying the exponent the
+        // wrong way round washes a mid-grey out towards white.
+        let mut chunks = HashMap::new();
+        chunks.insert("gAMA".to_owned(), 45455u32.to_be_bytes().to_vec());
+        let png = Png { chunks };
+
+        let pixels: [u8; 3] = [128, 128, 128];
+        let buffer = image::ImageBuffer::<image::Rgb<u8>, _>::from_raw(1, 1, pixels.to_vec())
+            .unwrap();
+        let image = image::DynamicImage::ImageRgb8(buffer);
+
+        let managed = color_manage_to_srgb(image, &png);
+        let channel = managed.get_pixel(0, 0)[0];
+        assert!(
+            channel < 160,
+            "expected gamma correction to stay close to the source value, got {}",
+            channel
+        );
+    }
 }
@@ -0,0 +1,49 @@
+/**
+    This file is part of Thumbnailer.
+
+    Thumbnailer is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License.
+
+    Thumbnailer is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Thumbnailer.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use imagepipe::{ImageSource, Pipeline};
+use std::path::Path;
+
+/// Decode a RAW camera file into a `DynamicImage`.
+///
+/// Many RAW files embed a full-size preview JPEG; decoding it directly is far
+/// cheaper than demosaicing the sensor buffer and is more than enough for the
+/// 128px/256px output sizes, so it is tried first. When no usable preview is
+/// present the full `rawloader`/`imagepipe` demosaicing pipeline is run.
+pub fn decode(path: &Path) -> Result<image::DynamicImage, String> {
+    let raw = rawloader::decode_file(path).map_err(|e| format!("Failed to decode RAW: {}", e))?;
+
+    if let Some(thumbnail) = &raw.thumbnail {
+        if let Ok(image) = image::load_from_memory_with_format(thumbnail, image::ImageFormat::Jpeg)
+        {
+            return Ok(image);
+        }
+    }
+
+    let source = ImageSource::Raw(raw);
+    let mut pipeline =
+        Pipeline::new_from_source(source).map_err(|e| format!("Failed to build pipeline: {}", e))?;
+    let image = pipeline
+        .output_8bit(None)
+        .map_err(|e| format!("Failed to run RAW pipeline: {}", e))?;
+
+    image::ImageBuffer::<image::Rgb<u8>, _>::from_raw(
+        image.width as u32,
+        image.height as u32,
+        image.data,
+    )
+    .map(image::DynamicImage::ImageRgb8)
+    .ok_or_else(|| "Failed to build image from RAW pipeline output".to_owned())
+}
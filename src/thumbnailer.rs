@@ -16,38 +16,18 @@
 use image::GenericImageView;
 use log::debug;
 use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::PathBuf;
-
-const PNG_TEXT_KIND: [u8; 4] = ['t' as u8, 'E' as u8, 'X' as u8, 't' as u8];
-
-pub fn text_chunk<S: Into<String>>(keyword: &str, text: S) -> Result<Vec<u8>, ()> {
-    let text = text.into();
-
-    if keyword.is_empty() || keyword.len() > 79 || keyword.contains('\0') {
-        return Err(());
-    }
-
-    if text.contains('\0') {
-        return Err(());
-    }
-
-    let text = text.replace("\r\n", "\n");
-
-    if text.is_empty() {
-        return Err(());
-    }
-    let data = {
-        let mut r = vec![];
-        r.extend_from_slice(keyword.as_bytes());
-        r.push(0);
-        r.extend_from_slice(text.as_bytes());
-        r
-    };
-
-    Ok(data)
+use std::path::{Path, PathBuf};
+
+/// Read the leading `tEXt` chunks of a PNG into a keyword -> text map, via the
+/// `png` module's chunk scanner. Returns `None` when the file is missing, is
+/// not a PNG, or is truncated.
+fn read_png_text_chunks(path: &Path) -> Option<HashMap<String, String>> {
+    let mut file = File::open(path).ok()?;
+    crate::png::read_text_chunks(&mut file).ok()
 }
 
 #[derive(Copy, Clone)]
@@ -56,6 +36,23 @@ pub enum ThumbSize {
     Large,
 }
 
+/// The kind of media a piece of `Work` refers to, used to select the decoder
+/// in `create_thumbnail_in_memory`.
+#[derive(Copy, Clone)]
+pub enum MediaKind {
+    Image,
+    #[cfg(feature = "video")]
+    Video,
+}
+
+/// The result of a successful `generate` call: whether a thumbnail was actually
+/// produced or an up-to-date one (or a fail marker) let us skip the work.
+#[derive(Copy, Clone)]
+pub enum Outcome {
+    Generated,
+    Skipped,
+}
+
 impl ThumbSize {
     fn size(&self) -> u32 {
         match self {
@@ -79,8 +76,10 @@ pub struct Thumbnailer {
     image: Option<image::DynamicImage>,
     thumbnail: Option<image::DynamicImage>,
     thumbnail_size: ThumbSize,
+    media_kind: MediaKind,
     pub filename: String,
     use_full_path_for_md5: bool,
+    force: bool,
 }
 
 impl Thumbnailer {
@@ -88,8 +87,10 @@ impl Thumbnailer {
         source_path: PathBuf,
         cache_path: PathBuf,
         image_size: ThumbSize,
+        media_kind: MediaKind,
         use_full_path_for_md5: bool,
-    ) -> Result<(), String> {
+        force: bool,
+    ) -> Result<Outcome, String> {
         let source_path = source_path
             .canonicalize()
             .map_err(|_e| "Cannot normalize input path")?;
@@ -101,13 +102,155 @@ impl Thumbnailer {
             image: None,
             thumbnail: None,
             thumbnail_size: image_size,
+            media_kind,
             use_full_path_for_md5: use_full_path_for_md5,
+            force,
         };
-        Thumbnailer::create_thumbnail_in_memory(thumbnailer)
-            .and_then(Thumbnailer::calculate_filename)
-            .and_then(Thumbnailer::calculate_destination)
-            .and_then(Thumbnailer::save_thumbnail_to_temp)
+        let thumbnailer = Thumbnailer::calculate_filename(thumbnailer)
+            .and_then(Thumbnailer::calculate_destination)?;
+
+        // Honour the freedesktop validity check: a thumbnail whose recorded
+        // source mtime and size still match the source file need not be
+        // regenerated.
+        if !thumbnailer.force && thumbnailer.is_thumbnail_valid() {
+            debug!(
+                "Thumbnail {} is up to date, skipping",
+                thumbnailer.destination_path.to_str().unwrap()
+            );
+            return Ok(Outcome::Skipped);
+        }
+
+        // Honour the freedesktop "fail" convention: a file we already failed to
+        // thumbnail at its current mtime is not worth retrying.
+        if !thumbnailer.force && thumbnailer.is_fail_marker_valid() {
+            debug!(
+                "Source {} has a matching fail marker, skipping",
+                thumbnailer.source_path.to_str().unwrap()
+            );
+            return Ok(Outcome::Skipped);
+        }
+
+        // Capture the few fields needed to record a failure before the
+        // thumbnailer is consumed by the generation pipeline.
+        let source_path = thumbnailer.source_path.clone();
+        let fail_path = thumbnailer.fail_marker_path();
+        let use_full_path_for_md5 = thumbnailer.use_full_path_for_md5;
+
+        // Only a decode failure means the source is genuinely un-thumbnailable;
+        // a fail marker written here correctly suppresses future retries. A
+        // failure further down the pipeline (writing the temp file, renaming
+        // it into place) is an I/O hiccup that may well succeed next time, so
+        // it must not be recorded as a permanent failure.
+        let thumbnailer = match Thumbnailer::create_thumbnail_in_memory(thumbnailer) {
+            Ok(t) => t,
+            Err(e) => {
+                debug!("Decoding failed for {}: {}", source_path.to_str().unwrap(), e);
+                if let Err(marker_err) =
+                    Thumbnailer::write_fail_marker(&fail_path, &source_path, use_full_path_for_md5)
+                {
+                    debug!("Failed to write fail marker: {}", marker_err);
+                }
+                return Err(e);
+            }
+        };
+
+        Thumbnailer::save_thumbnail_to_temp(thumbnailer)
             .and_then(Thumbnailer::move_thumbnail_to_destination)
+            .map(|_| Outcome::Generated)
+            .map_err(|e| {
+                debug!("Writing thumbnail failed for {}: {}", source_path.to_str().unwrap(), e);
+                e
+            })
+    }
+
+    /// Check whether an existing thumbnail is still valid for the current
+    /// source file, comparing the stored `Thumb::MTime`/`Thumb::Size` against
+    /// the source's modification time and length.
+    fn is_thumbnail_valid(&self) -> bool {
+        let chunks = match read_png_text_chunks(&self.destination_path) {
+            Some(c) => c,
+            None => return false,
+        };
+        let metadata = match std::fs::metadata(&self.source_path) {
+            Ok(m) => m,
+            Err(_) => return false,
+        };
+        let mtime = match Thumbnailer::source_mtime_secs(&self.source_path) {
+            Some(m) => m,
+            None => return false,
+        };
+
+        chunks.get("Thumb::MTime") == Some(&mtime.to_string())
+            && chunks.get("Thumb::Size") == Some(&metadata.len().to_string())
+    }
+
+    /// Location of the freedesktop fail marker for this source, under
+    /// `<cache>/fail/thumbnailer/<md5>.png`.
+    fn fail_marker_path(&self) -> PathBuf {
+        self.cache_path
+            .join("fail")
+            .join("thumbnailer")
+            .join(&self.filename)
+    }
+
+    /// Whether a fail marker exists whose `Thumb::MTime` still matches the
+    /// source file, meaning the file is known-bad at its current revision.
+    fn is_fail_marker_valid(&self) -> bool {
+        let chunks = match read_png_text_chunks(&self.fail_marker_path()) {
+            Some(c) => c,
+            None => return false,
+        };
+        let mtime = match Thumbnailer::source_mtime_secs(&self.source_path) {
+            Some(m) => m,
+            None => return false,
+        };
+        chunks.get("Thumb::MTime") == Some(&mtime.to_string())
+    }
+
+    /// Source modification time in whole seconds since the Unix epoch.
+    fn source_mtime_secs(source_path: &Path) -> Option<u64> {
+        std::fs::metadata(source_path)
+            .ok()?
+            .modified()
+            .ok()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs())
+    }
+
+    /// Write a 1x1 marker PNG carrying `Thumb::URI`, `Thumb::MTime` and
+    /// `Thumb::Size` so the source is not retried until it changes.
+    fn write_fail_marker(
+        fail_path: &Path,
+        source_path: &Path,
+        use_full_path_for_md5: bool,
+    ) -> Result<(), String> {
+        let parent = fail_path
+            .parent()
+            .ok_or_else(|| "Fail marker has no parent directory".to_owned())?;
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create fail directory: {}", e))?;
+
+        let metadata = std::fs::metadata(source_path)
+            .map_err(|e| format!("Failed to stat source: {}", e))?;
+        let mtime = Thumbnailer::source_mtime_secs(source_path)
+            .ok_or_else(|| "Failed to read source mtime".to_owned())?;
+        let uri = Thumbnailer::calculate_path_uri(use_full_path_for_md5, &source_path.to_path_buf());
+
+        // The marker's only meaningful content is its provenance; its image is
+        // a single black pixel, the smallest a valid PNG can hold.
+        let bytes = crate::png::encode_thumbnail(
+            &[0u8],
+            1,
+            1,
+            crate::png::ColorType::Gray,
+            &uri,
+            mtime,
+            metadata.len(),
+        );
+        std::fs::write(fail_path, &bytes)
+            .map_err(|e| format!("Failed to write fail marker: {}", e))?;
+        Ok(())
     }
 
     fn calculate_path_uri(use_full_path_for_md5: bool, path: &PathBuf) -> String {
@@ -172,14 +315,72 @@ impl Thumbnailer {
         hex::encode(vec)
     }
 
+    fn load_source_image(thumbnailer: &Thumbnailer) -> Result<image::DynamicImage, String> {
+        match thumbnailer.media_kind {
+            MediaKind::Image => {
+                #[cfg(feature = "heif")]
+                {
+                    let extension = thumbnailer
+                        .source_path
+                        .extension()
+                        .and_then(OsStr::to_str)
+                        .map(str::to_lowercase);
+                    if let Some(ext) = extension {
+                        if ext == "heic" || ext == "heif" || ext == "avif" {
+                            return crate::heif::decode(&thumbnailer.source_path);
+                        }
+                    }
+                }
+                #[cfg(feature = "raw")]
+                {
+                    let extension = thumbnailer
+                        .source_path
+                        .extension()
+                        .and_then(OsStr::to_str)
+                        .map(str::to_lowercase);
+                    if let Some(ext) = extension {
+                        if matches!(
+                            ext.as_str(),
+                            "cr2" | "nef" | "arw" | "dng" | "rw2" | "orf" | "raf"
+                        ) {
+                            return crate::raw::decode(&thumbnailer.source_path);
+                        }
+                    }
+                }
+                let image_format = image::ImageFormat::from_path(&thumbnailer.source_path)
+                    .map_err(|_| "Failed to obtain file format".to_owned())?;
+                let file = File::open(&thumbnailer.source_path)
+                    .map_err(|_| "File to open file".to_owned())?;
+                let reader = BufReader::new(file);
+                let image =
+                    image::load(reader, image_format).map_err(|_| "Failed to load file".to_owned())?;
+
+                // PNGs may carry an eXIf chunk recording the display
+                // orientation and iCCP/sRGB/gAMA chunks describing their
+                // colour space; auto-orient and colour-manage before scaling
+                // so the thumbnail matches what viewers show for the
+                // full-size image. A file that fails to re-parse as our own
+                // chunk scanner is left as decoded.
+                if image_format == image::ImageFormat::Png {
+                    if let Ok(mut file) = File::open(&thumbnailer.source_path) {
+                        if let Ok(png) = crate::png::Png::decode(&mut file) {
+                            let image = match png.exif() {
+                                Some(exif) => crate::png::apply_orientation(image, exif.orientation),
+                                None => image,
+                            };
+                            return Ok(crate::png::color_manage_to_srgb(image, &png));
+                        }
+                    }
+                }
+                Ok(image)
+            }
+            #[cfg(feature = "video")]
+            MediaKind::Video => crate::video::decode_frame(&thumbnailer.source_path),
+        }
+    }
+
     fn create_thumbnail_in_memory(mut thumbnailer: Thumbnailer) -> Result<Thumbnailer, String> {
-        let image_format = image::ImageFormat::from_path(&thumbnailer.source_path)
-            .map_err(|_| "Failed to obtain file format".to_owned())?;
-        let file =
-            File::open(&thumbnailer.source_path).map_err(|_| "File to open file".to_owned())?;
-        let reader = BufReader::new(file);
-        let image =
-            image::load(reader, image_format).map_err(|_| "Failed to load file".to_owned())?;
+        let image = Thumbnailer::load_source_image(&thumbnailer)?;
         let thumbnail = image.thumbnail(
             thumbnailer.thumbnail_size.size(),
             thumbnailer.thumbnail_size.size(),
@@ -218,81 +419,48 @@ impl Thumbnailer {
 
     fn save_thumbnail_to_temp(thumbnailer: Thumbnailer) -> Result<Thumbnailer, String> {
         let temp_path = format!("{}.tmp", thumbnailer.destination_path.to_str().unwrap());
-        let output = std::fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(&temp_path)
-            .map_err(|e| format!("Failed to open thumbnailer in temporary dir: {}", e))?;
 
         let thumbnail = thumbnailer.thumbnail.as_ref().unwrap();
-        let (ct, bits) = match thumbnail.color() {
-            image::ColorType::L8 => (png::ColorType::Grayscale, png::BitDepth::Eight),
-            image::ColorType::L16 => (png::ColorType::Grayscale, png::BitDepth::Sixteen),
-            image::ColorType::La8 => (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight),
-            image::ColorType::La16 => (png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen),
-            image::ColorType::Rgb8 => (png::ColorType::RGB, png::BitDepth::Eight),
-            image::ColorType::Rgb16 => (png::ColorType::RGB, png::BitDepth::Sixteen),
-            image::ColorType::Rgba8 => (png::ColorType::RGBA, png::BitDepth::Eight),
-            image::ColorType::Rgba16 => (png::ColorType::RGBA, png::BitDepth::Sixteen),
-            _ => return Err("unsupported format".to_string()),
+        let (color, pixels) = if thumbnail.color().has_alpha() {
+            (crate::png::ColorType::Rgba, thumbnail.to_rgba8().into_raw())
+        } else {
+            (crate::png::ColorType::Rgb, thumbnail.to_rgb8().into_raw())
         };
-        let mut encoder = png::Encoder::new(output, thumbnail.width(), thumbnail.height());
-        encoder.set_color(ct);
-        encoder.set_depth(bits);
-        let mut writer = encoder
-            .write_header()
-            .map_err(|e| format!("Error writing PNG header: {}", e))?;
 
         let uri_raw = Thumbnailer::calculate_path_uri(
             thumbnailer.use_full_path_for_md5,
             &thumbnailer.source_path,
         );
-        writer
-            .write_chunk(PNG_TEXT_KIND, &text_chunk("Thumb::URI", uri_raw).unwrap())
-            .map_err(|e| format!("Error writing PNG chunk: {}", e))?;
-
         let metadata = std::fs::metadata(&thumbnailer.source_path).unwrap();
-        let mtime_raw = metadata.modified().unwrap();
-        let mtime_raw = mtime_raw.duration_since(std::time::UNIX_EPOCH).unwrap();
-        let mtime_raw = mtime_raw.as_secs();
-        writer
-            .write_chunk(
-                PNG_TEXT_KIND,
-                &text_chunk("Thumb::MTime", mtime_raw.to_string()).unwrap(),
-            )
-            .map_err(|e| format!("Error writing PNG chunk: {}", e))?;
+        let mtime_raw = metadata
+            .modified()
+            .unwrap()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let image = thumbnailer.image.as_ref().unwrap();
+        // A cheap inline placeholder a viewer can paint immediately, before
+        // the thumbnail itself has loaded.
+        let blurhash = crate::blurhash::blurhash(&pixels, thumbnail.width(), thumbnail.height(), 4, 3);
+
+        let texts = [
+            ("Thumb::URI", uri_raw),
+            ("Thumb::MTime", mtime_raw.to_string()),
+            ("Thumb::Size", metadata.len().to_string()),
+            ("Thumb::Image::Width", image.width().to_string()),
+            ("Thumb::Image::Height", image.height().to_string()),
+            ("Thumb::Blurhash", blurhash),
+        ];
+        let bytes = crate::png::encode_with_text(
+            &pixels,
+            thumbnail.width(),
+            thumbnail.height(),
+            color,
+            &texts,
+        );
 
-        let size_raw = metadata.len();
-        writer
-            .write_chunk(
-                PNG_TEXT_KIND,
-                &text_chunk("Thumb::Size", size_raw.to_string()).unwrap(),
-            )
-            .map_err(|e| format!("Error writing PNG chunk: {}", e))?;
-        writer
-            .write_chunk(
-                PNG_TEXT_KIND,
-                &text_chunk(
-                    "Thumb::Image::Width",
-                    thumbnailer.image.as_ref().unwrap().width().to_string(),
-                )
-                .unwrap(),
-            )
-            .map_err(|e| format!("Error writing PNG chunk: {}", e))?;
-        writer
-            .write_chunk(
-                PNG_TEXT_KIND,
-                &text_chunk(
-                    "Thumb::Image::Height",
-                    thumbnailer.image.as_ref().unwrap().height().to_string(),
-                )
-                .unwrap(),
-            )
-            .map_err(|e| format!("Error writing PNG chunk: {}", e))?;
-        writer
-            .write_image_data(&thumbnail.to_bytes())
-            .map_err(|e| format!("Error writing PNG image data: {}", e))?;
+        std::fs::write(&temp_path, &bytes)
+            .map_err(|e| format!("Failed to write thumbnailer in temporary dir: {}", e))?;
         Ok(thumbnailer)
     }
 
@@ -307,7 +475,7 @@ impl Thumbnailer {
 
 #[cfg(test)]
 mod tests {
-    use crate::thumbnailer::{ThumbSize, Thumbnailer};
+    use crate::thumbnailer::{MediaKind, ThumbSize, Thumbnailer};
     use std::path::{Path, PathBuf};
 
     #[test]
@@ -334,20 +502,26 @@ mod tests {
             input_path.clone(),
             PathBuf::from("/tmp/thumbnailer"),
             ThumbSize::Normal,
+            MediaKind::Image,
             true,
+            false,
         )
         .unwrap();
         Thumbnailer::generate(
             input_path.clone(),
             PathBuf::from("/tmp/thumbnailer"),
             ThumbSize::Large,
+            MediaKind::Image,
             true,
+            false,
         )
         .unwrap();
         Thumbnailer::generate(
             input_path.clone(),
             PathBuf::from("/tmp/thumbnailer"),
             ThumbSize::Normal,
+            MediaKind::Image,
+            false,
             false,
         )
         .unwrap();
@@ -355,6 +529,8 @@ mod tests {
             input_path.clone(),
             PathBuf::from("/tmp/thumbnailer"),
             ThumbSize::Large,
+            MediaKind::Image,
+            false,
             false,
         )
         .unwrap();
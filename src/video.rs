@@ -0,0 +1,102 @@
+/**
+    This file is part of Thumbnailer.
+
+    Thumbnailer is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License.
+
+    Thumbnailer is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Thumbnailer.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use ffmpeg_next as ffmpeg;
+use std::path::Path;
+
+/// Decode a single representative frame from a video file and return it as an
+/// RGB `DynamicImage`, ready for the existing `thumbnail()`/PNG pipeline.
+///
+/// The timestamp is picked at ten percent of the stream duration (a common
+/// heuristic that avoids black intro frames); when the duration is unknown we
+/// fall back to the first frame the decoder yields.
+pub fn decode_frame(path: &Path) -> Result<image::DynamicImage, String> {
+    ffmpeg::init().map_err(|e| format!("Failed to initialize ffmpeg: {}", e))?;
+
+    let mut input =
+        ffmpeg::format::input(&path).map_err(|e| format!("Failed to open video: {}", e))?;
+    let stream = input
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .ok_or_else(|| "No video stream found".to_owned())?;
+    let stream_index = stream.index();
+
+    let context = ffmpeg::codec::context::Context::from_parameters(stream.parameters())
+        .map_err(|e| format!("Failed to build codec context: {}", e))?;
+    let mut decoder = context
+        .decoder()
+        .video()
+        .map_err(|e| format!("Failed to open video decoder: {}", e))?;
+
+    let mut scaler = ffmpeg::software::scaling::context::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        ffmpeg::format::Pixel::RGB24,
+        decoder.width(),
+        decoder.height(),
+        ffmpeg::software::scaling::flag::Flags::BILINEAR,
+    )
+    .map_err(|e| format!("Failed to create scaler: {}", e))?;
+
+    // Seek to duration * 0.1 when the duration is available.
+    let duration = input.duration();
+    if duration > 0 {
+        let timestamp = (duration as f64 * 0.1) as i64;
+        let _ = input.seek(timestamp, ..timestamp);
+    }
+
+    let width = decoder.width();
+    let height = decoder.height();
+
+    for (stream, packet) in input.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+        decoder
+            .send_packet(&packet)
+            .map_err(|e| format!("Failed to send packet to decoder: {}", e))?;
+        let mut decoded = ffmpeg::util::frame::video::Video::empty();
+        if decoder.receive_frame(&mut decoded).is_ok() {
+            let mut rgb = ffmpeg::util::frame::video::Video::empty();
+            scaler
+                .run(&decoded, &mut rgb)
+                .map_err(|e| format!("Failed to scale frame: {}", e))?;
+            return wrap_frame(&rgb, width, height);
+        }
+    }
+
+    Err("Failed to decode any video frame".to_owned())
+}
+
+/// Copy an RGB24 frame (whose rows are padded to `stride`) into a contiguous
+/// buffer and wrap it into a `DynamicImage`.
+fn wrap_frame(
+    frame: &ffmpeg::util::frame::video::Video,
+    width: u32,
+    height: u32,
+) -> Result<image::DynamicImage, String> {
+    let stride = frame.stride(0);
+    let source = frame.data(0);
+    let row_bytes = (width * 3) as usize;
+    let mut buffer = Vec::with_capacity(row_bytes * height as usize);
+    for y in 0..height as usize {
+        let start = y * stride;
+        buffer.extend_from_slice(&source[start..start + row_bytes]);
+    }
+    image::ImageBuffer::<image::Rgb<u8>, _>::from_raw(width, height, buffer)
+        .map(image::DynamicImage::ImageRgb8)
+        .ok_or_else(|| "Failed to build image from decoded frame".to_owned())
+}